@@ -1,9 +1,117 @@
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs::{self, File};
-use std::io;
-use std::os::unix::fs::{MetadataExt, PermissionsExt};
-use std::os::unix::io::FromRawFd;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::platform;
+
+/// A `Mutex`/`Condvar`-backed in-process lock, serializing same-process
+/// writers that all point at the same `(directory, prefix)`. The OS-level
+/// `fcntl`/`LockFileEx` lock taken by [`AtomicFile::lock`] is a per-process
+/// lock on most platforms and would otherwise let a second thread in the same
+/// process acquire it immediately, defeating the point of serializing local
+/// writers — including two independently-constructed `AtomicFile::new`
+/// handles pointed at the same path, which is the pattern this crate's own
+/// doc comment advertises as supported.
+#[derive(Debug, Default)]
+struct InProcessLock {
+    locked: Mutex<bool>,
+    unlocked: Condvar,
+}
+
+/// Process-wide registry handing out one shared [`InProcessLock`] per
+/// `(directory, prefix)`, so that every `AtomicFile` pointed at the same path
+/// serializes against the others regardless of whether they're clones of one
+/// handle or independently constructed. Entries are held weakly so that once
+/// every `AtomicFile` for a given path is dropped, the lock is freed instead
+/// of accumulating for the life of the process.
+fn shared_write_lock(directory: &Path, prefix: &str) -> Arc<InProcessLock> {
+    static REGISTRY: OnceLock<Mutex<HashMap<(PathBuf, String), Weak<InProcessLock>>>> =
+        OnceLock::new();
+    let mut registry = REGISTRY.get_or_init(Default::default).lock().unwrap();
+    let key = (directory.to_path_buf(), prefix.to_string());
+    if let Some(lock) = registry.get(&key).and_then(Weak::upgrade) {
+        return lock;
+    }
+    let lock = Arc::new(InProcessLock::default());
+    registry.insert(key, Arc::downgrade(&lock));
+    lock
+}
+
+impl InProcessLock {
+    fn acquire(&self, deadline: Option<Instant>) -> io::Result<()> {
+        let mut locked = self.locked.lock().unwrap();
+        while *locked {
+            locked = match deadline {
+                None => self.unlocked.wait(locked).unwrap(),
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "timed out waiting to acquire the in-process writer lock",
+                        ));
+                    }
+                    let (locked, timeout) = self.unlocked.wait_timeout(locked, remaining).unwrap();
+                    if timeout.timed_out() && *locked {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "timed out waiting to acquire the in-process writer lock",
+                        ));
+                    }
+                    locked
+                }
+            };
+        }
+        *locked = true;
+        Ok(())
+    }
+
+    fn release(&self) {
+        *self.locked.lock().unwrap() = false;
+        self.unlocked.notify_one();
+    }
+}
+
+/// 4-byte marker identifying a checksummed version file, so that a reader
+/// can tell headered versions apart from version files written before
+/// [`AtomicFile::with_checksums`] was turned on, without needing to be told
+/// which is which up front.
+const CHECKSUM_MAGIC: [u8; 4] = *b"acf1";
+
+/// Size in bytes of the header prepended to a version file's payload when
+/// checksums are enabled: [`CHECKSUM_MAGIC`], followed by an 8-byte
+/// little-endian payload length, followed by an 8-byte little-endian xxh3
+/// hash of the payload.
+const CHECKSUM_HEADER_LEN: usize = CHECKSUM_MAGIC.len() + 16;
+
+fn checksum_header(payload: &[u8]) -> [u8; CHECKSUM_HEADER_LEN] {
+    let mut header = [0; CHECKSUM_HEADER_LEN];
+    header[..4].copy_from_slice(&CHECKSUM_MAGIC);
+    header[4..12].copy_from_slice(&(payload.len() as u64).to_le_bytes());
+    header[12..].copy_from_slice(&xxhash_rust::xxh3::xxh3_64(payload).to_le_bytes());
+    header
+}
+
+/// Rewrite `file`'s contents in place as `header || payload`, where `header`
+/// is computed over the payload already written to `file`.
+fn prepend_checksum_header(file: &File) -> io::Result<()> {
+    let mut reader = file;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut payload = Vec::new();
+    reader.read_to_end(&mut payload)?;
+
+    let header = checksum_header(&payload);
+    file.set_len(0)?;
+    let mut writer = file;
+    writer.seek(SeekFrom::Start(0))?;
+    writer.write_all(&header)?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
 
 /// An anonymous temporary file, which removes itself on drop.
 pub struct TmpFile {
@@ -13,12 +121,8 @@ pub struct TmpFile {
 
 impl TmpFile {
     pub fn create_in(directory: impl AsRef<Path>) -> io::Result<Self> {
-        let template = directory.as_ref().join("XXXXXX");
-        let (fd, path) = nix::unistd::mkstemp(&template)?;
-        Ok(Self {
-            file: unsafe { File::from_raw_fd(fd) },
-            path,
-        })
+        let (file, path) = platform::create_temp_file(directory.as_ref())?;
+        Ok(Self { file, path })
     }
 }
 
@@ -44,7 +148,7 @@ impl Drop for TmpFile {
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Debug)]
+#[derive(Clone, Debug)]
 /// An `AtomicFile` emulates an on-disk `Atomic<Option<File>>`. Calling `load`
 /// returns the latest known version of the file, and the file contents can be
 /// updated by using the `compare_and_swap` operation.
@@ -59,8 +163,19 @@ impl Drop for TmpFile {
 pub struct AtomicFile {
     directory: PathBuf,
     prefix: String,
+    auto_gc: Option<(usize, Duration)>,
+    checksummed: bool,
+    write_lock: Arc<InProcessLock>,
 }
 
+impl PartialEq for AtomicFile {
+    fn eq(&self, other: &Self) -> bool {
+        self.directory == other.directory && self.prefix == other.prefix
+    }
+}
+
+impl Eq for AtomicFile {}
+
 fn parse_version(filename: &OsStr, prefix: &str) -> Option<usize> {
     let filename = filename.to_str()?;
     if !filename.starts_with(prefix) {
@@ -92,7 +207,37 @@ impl AtomicFile {
             })?
             .to_string();
         prefix.push('.');
-        Ok(Self { directory, prefix })
+        let write_lock = shared_write_lock(&directory, &prefix);
+        Ok(Self {
+            directory,
+            prefix,
+            auto_gc: None,
+            checksummed: false,
+            write_lock,
+        })
+    }
+
+    /// Enable automatic garbage-collection of stale versions after every
+    /// successful [`compare_and_swap`](Self::compare_and_swap), with the same
+    /// semantics as [`gc`](Self::gc).
+    pub fn with_auto_gc(mut self, keep_last: usize, min_age: Duration) -> Self {
+        self.auto_gc = Some((keep_last, min_age));
+        self
+    }
+
+    /// Make every version written through [`compare_and_swap`](Self::compare_and_swap)
+    /// carry a small header (magic marker, payload length, xxh3 hash) so that
+    /// truncation or corruption can be detected on read via
+    /// [`load_verified`](Self::load_verified) / [`ReadOnlyFile::read_checked`].
+    ///
+    /// Reads auto-detect the header via its magic marker, so turning this on
+    /// for an `AtomicFile` pointed at a directory with pre-existing
+    /// headerless versions doesn't break reading them: older, headerless
+    /// versions are still read as raw payloads, and only versions written
+    /// after this point carry (and are verified against) the header.
+    pub fn with_checksums(mut self) -> Self {
+        self.checksummed = true;
+        self
     }
 
     fn latest_version(&self) -> io::Result<usize> {
@@ -116,6 +261,37 @@ impl AtomicFile {
         Ok(ReadOnlyFile { version, path })
     }
 
+    /// Load the latest known version of the file, verifying and stripping
+    /// its checksum header if it has one. See [`ReadOnlyFile::read_checked`].
+    pub fn load_verified(&self) -> io::Result<Option<Vec<u8>>> {
+        self.load()?.read_checked()
+    }
+
+    /// Open an arbitrary past snapshot of the file, identified by its version
+    /// number. Unlike [`load`](Self::load), this performs no I/O by itself:
+    /// any error (e.g. the version having been garbage-collected) only
+    /// surfaces once the returned `ReadOnlyFile` is actually opened.
+    pub fn load_version(&self, version: usize) -> ReadOnlyFile {
+        let path = self.path(version);
+        ReadOnlyFile { version, path }
+    }
+
+    /// List every version of the file still present on disk, from the same
+    /// directory scan [`latest_version`](Self::latest_version) performs, most
+    /// recent first.
+    pub fn versions(&self) -> io::Result<impl Iterator<Item = (usize, ReadOnlyFile)>> {
+        let mut versions = Vec::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            if let Some(version) = parse_version(&entry.file_name(), &self.prefix) {
+                let path = entry.path();
+                versions.push((version, ReadOnlyFile { version, path }));
+            }
+        }
+        versions.sort_unstable_by_key(|(version, _)| std::cmp::Reverse(*version));
+        Ok(versions.into_iter())
+    }
+
     /// Create a new temporary file, which can be written to.
     pub fn make_temp(&self) -> io::Result<TmpFile> {
         TmpFile::create_in(&self.directory)
@@ -131,32 +307,137 @@ impl AtomicFile {
     /// well.
     pub fn compare_and_swap(&self, current: &ReadOnlyFile, new: TmpFile) -> io::Result<()> {
         let new_path = self.path(current.version + 1);
+        if self.checksummed {
+            prepend_checksum_header(&new.file)?;
+        }
         (&new.file).sync_data()?;
-        // May return `EEXIST`.
-        let res = nix::unistd::linkat(
-            None,
-            &new.path,
-            None,
-            &new_path,
-            nix::unistd::LinkatFlags::NoSymlinkFollow,
-        );
-        if let Err(err) = res {
-            // From open(2) manual page:
-            //
-            // "[...] create a unique file on the same filesystem (e.g.,
-            // incorporating hostname and PID), and use link(2) to make a link
-            // to the lockfile. If link(2) returns 0, the lock is successful.
-            // Otherwise, use stat(2) on the unique file to check if its link
-            // count has increased to 2, in which case the lock is also
-            // succesful."
-            if new.path.metadata()?.nlink() != 2 {
-                Err(err)?;
+        // May return `AlreadyExists`.
+        if let Err(err) = platform::link_new(&new.path, &new_path) {
+            if err.kind() != io::ErrorKind::AlreadyExists {
+                return Err(err);
+            }
+            // Our own link may actually have gone through despite the racy
+            // error (filesystems don't all report this the same way), so
+            // only treat this as a genuine conflict if `new_path` isn't
+            // really our own temp file.
+            if !platform::is_same_file(&new.path, &new_path)? {
+                return Err(err);
             }
         }
         // Set read rights to everyone, don't care if that fails.
-        let _ = fs::set_permissions(new_path, fs::Permissions::from_mode(0o644));
+        let _ = platform::set_world_readable(&new_path);
+        if let Some((keep_last, min_age)) = self.auto_gc {
+            self.gc(keep_last, min_age)?;
+        }
         Ok(())
     }
+
+    /// Remove stale versions of the file, keeping the `keep_last` versions
+    /// preceding the latest one (the latest version and the version-0
+    /// sentinel are never removed).
+    ///
+    /// A version is only unlinked once its mtime is older than `min_age`, so
+    /// that a `ReadOnlyFile` obtained by another thread, process or machine
+    /// which has not called `open()` yet is still given a grace window during
+    /// which its path is guaranteed to resolve.
+    pub fn gc(&self, keep_last: usize, min_age: Duration) -> io::Result<()> {
+        let latest = self.latest_version()?;
+        let threshold = latest.saturating_sub(keep_last);
+        let now = SystemTime::now();
+        for entry in fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            let version = match parse_version(&entry.file_name(), &self.prefix) {
+                Some(version) => version,
+                None => continue,
+            };
+            if version == 0 || version == latest || version >= threshold {
+                continue;
+            }
+            // Another writer's concurrent `gc()` may have already unlinked
+            // this entry between our `read_dir` and this `metadata()` call;
+            // that's not our problem to report, so just skip it like the
+            // `remove_file` below already tolerates losing the same race.
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            let age = match now.duration_since(metadata.modified()?) {
+                Ok(age) => age,
+                Err(_) => continue,
+            };
+            if age < min_age {
+                continue;
+            }
+            let _ = fs::remove_file(entry.path());
+        }
+        Ok(())
+    }
+
+    fn lock_path(&self) -> PathBuf {
+        self.directory.join(format!("{}lock", self.prefix))
+    }
+
+    /// Acquire an advisory exclusive lock on a dedicated lock
+    /// file in the directory, so that only one local writer at a time builds
+    /// a candidate version and races the `linkat` CAS. Cross-machine
+    /// correctness still relies solely on the CAS itself; this only avoids
+    /// wasted I/O under local contention.
+    ///
+    /// `fcntl`/`LockFileEx` are a lock per *process* (or per handle, depending
+    /// on the platform), not per thread, so they alone wouldn't serialize two
+    /// threads of the same process racing against the same path — including
+    /// two independently-constructed `AtomicFile::new` handles, not just
+    /// clones of one. An in-process `Mutex`, shared process-wide by
+    /// `(directory, prefix)`, is taken first to cover that case, then the
+    /// OS-level lock to cover other processes.
+    ///
+    /// `timeout` of `None` blocks until the lock is acquired; `Some(timeout)`
+    /// gives up with an `io::ErrorKind::TimedOut` error past that duration,
+    /// which bounds the in-process wait and the OS-level wait together. The
+    /// lock is released when the returned `WriterLock` is dropped, so a
+    /// crashed writer doesn't wedge the others.
+    pub fn lock(&self, timeout: Option<Duration>) -> io::Result<WriterLock> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.write_lock.acquire(deadline)?;
+
+        let open_and_lock = || -> io::Result<File> {
+            let file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(self.lock_path())?;
+            let remaining =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            platform::lock_exclusive(&file, remaining)?;
+            Ok(file)
+        };
+        match open_and_lock() {
+            Ok(file) => Ok(WriterLock {
+                file,
+                in_process: self.write_lock.clone(),
+            }),
+            Err(err) => {
+                self.write_lock.release();
+                Err(err)
+            }
+        }
+    }
+}
+
+/// An advisory lock on an `AtomicFile`'s writer lock file, obtained via
+/// [`AtomicFile::lock`]. Released when dropped.
+pub struct WriterLock {
+    file: File,
+    in_process: Arc<InProcessLock>,
+}
+
+impl Drop for WriterLock {
+    fn drop(&mut self) {
+        let _ = platform::unlock(&self.file);
+        self.in_process.release();
+    }
 }
 
 #[derive(Clone)]
@@ -176,4 +457,91 @@ impl ReadOnlyFile {
             Ok(None)
         }
     }
+
+    /// The version number this snapshot points to.
+    pub fn version(&self) -> usize {
+        self.version
+    }
+
+    /// Read the whole file, stripping and verifying its checksum header if
+    /// it has one (detected via [`CHECKSUM_MAGIC`]). Returns an
+    /// `io::ErrorKind::InvalidData` error if the header is present but the
+    /// file is truncated or its payload doesn't match the stored hash.
+    ///
+    /// A version file written without a checksum header, e.g. because it
+    /// predates [`AtomicFile::with_checksums`], is returned as-is.
+    pub fn read_checked(&self) -> io::Result<Option<Vec<u8>>> {
+        let file = match self.open()? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        let mut buf = Vec::new();
+        io::BufReader::new(file).read_to_end(&mut buf)?;
+        Ok(Some(strip_checksum_header(buf)?))
+    }
+}
+
+/// Verify and strip the checksum header from `buf`, a whole version file's
+/// contents, if it has one; otherwise pass it through unchanged. Detecting
+/// the header via [`CHECKSUM_MAGIC`] instead of a per-`AtomicFile` flag is
+/// what lets [`AtomicFile::with_checksums`] be turned on for a directory that
+/// already has headerless versions in it without breaking reads of those.
+/// Shared by the sync and async read paths so they stay in sync with what
+/// [`AtomicFile::with_checksums`] writes.
+pub(crate) fn strip_checksum_header(buf: Vec<u8>) -> io::Result<Vec<u8>> {
+    if buf.len() < CHECKSUM_HEADER_LEN || buf[..CHECKSUM_MAGIC.len()] != CHECKSUM_MAGIC {
+        return Ok(buf);
+    }
+    let (header, payload) = buf.split_at(CHECKSUM_HEADER_LEN);
+    let len = u64::from_le_bytes(header[4..12].try_into().unwrap()) as usize;
+    let hash = u64::from_le_bytes(header[12..].try_into().unwrap());
+    if len != payload.len() || xxhash_rust::xxh3::xxh3_64(payload) != hash {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "version file failed checksum verification",
+        ));
+    }
+    Ok(payload.to_vec())
+}
+
+#[cfg(feature = "async")]
+impl AtomicFile {
+    /// Async equivalent of [`load`](Self::load), offloaded onto a blocking
+    /// thread pool since listing the directory is a blocking syscall.
+    pub async fn load_async(&self) -> io::Result<ReadOnlyFile> {
+        let this = self.clone();
+        async_std::task::spawn_blocking(move || this.load()).await
+    }
+
+    /// Async equivalent of [`make_temp`](Self::make_temp).
+    pub async fn make_temp_async(&self) -> io::Result<TmpFile> {
+        let this = self.clone();
+        async_std::task::spawn_blocking(move || this.make_temp()).await
+    }
+
+    /// Async equivalent of [`compare_and_swap`](Self::compare_and_swap). The
+    /// blocking `fsync`/`linkat` dance runs on a blocking thread pool, and
+    /// the CAS retry loop in [`modify_async`](crate::modify_async) awaits between attempts instead
+    /// of spinning synchronously.
+    pub async fn compare_and_swap_async(
+        &self,
+        current: &ReadOnlyFile,
+        new: TmpFile,
+    ) -> io::Result<()> {
+        let this = self.clone();
+        let current = current.clone();
+        async_std::task::spawn_blocking(move || this.compare_and_swap(&current, new)).await
+    }
+}
+
+#[cfg(feature = "async")]
+impl ReadOnlyFile {
+    /// Async equivalent of [`open`](Self::open). The returned handle is
+    /// produced by converting the finalized file's raw fd once `open()` has
+    /// run on the blocking pool.
+    pub async fn open_async(&self) -> io::Result<Option<async_std::fs::File>> {
+        let this = self.clone();
+        let file = async_std::task::spawn_blocking(move || this.open()).await?;
+        Ok(file.map(async_std::fs::File::from))
+    }
 }
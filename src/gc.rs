@@ -0,0 +1,17 @@
+use atomic_file::{modify, AtomicFile};
+use std::io::{self, Read};
+use std::time::Duration;
+
+fn main() -> io::Result<()> {
+    let file = AtomicFile::new("file_gc")?.with_auto_gc(0, Duration::ZERO);
+    for i in 0..3 {
+        modify(&file, |_| format!("v{i}").into_bytes())?;
+    }
+    // `keep_last == 0` must still never collect the version that was just
+    // written: only the versions strictly preceding the latest are eligible.
+    let mut data = String::new();
+    file.load()?.open()?.unwrap().read_to_string(&mut data)?;
+    assert_eq!(data, "v2");
+    assert_eq!(file.versions()?.count(), 1);
+    Ok(())
+}
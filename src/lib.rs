@@ -1,19 +1,17 @@
 mod atomic;
+mod platform;
 
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use std::io::{self, Read, Write};
+use std::io::{self, Write};
+use std::time::Duration;
 
 pub use self::atomic::AtomicFile;
 
 pub fn modify(x: &AtomicFile, mut op: impl FnMut(&[u8]) -> Vec<u8>) -> io::Result<()> {
-    let mut buf = vec![];
     loop {
         let latest = x.load()?;
-        buf.clear();
-        if let Some(mut file) = latest.open()? {
-            file.read_to_end(&mut buf)?;
-        }
+        let buf = latest.read_checked()?.unwrap_or_default();
         let data = op(&buf);
         let tmp = x.make_temp()?;
         (&tmp).write_all(&data)?;
@@ -26,6 +24,49 @@ pub fn modify(x: &AtomicFile, mut op: impl FnMut(&[u8]) -> Vec<u8>) -> io::Resul
     }
 }
 
+/// Like [`modify`], but first takes the `AtomicFile`'s advisory writer lock
+/// (see [`AtomicFile::lock`]) so that concurrent local writers serialize
+/// instead of racing each other's `compare_and_swap` and wasting I/O.
+/// `timeout` of `None` blocks until the lock is acquired; `Some(timeout)`
+/// returns an `io::ErrorKind::TimedOut` error past that duration.
+pub fn modify_locked(
+    x: &AtomicFile,
+    timeout: Option<Duration>,
+    op: impl FnMut(&[u8]) -> Vec<u8>,
+) -> io::Result<()> {
+    let _lock = x.lock(timeout)?;
+    modify(x, op)
+}
+
+/// Async equivalent of [`modify`], which offloads the blocking syscalls onto
+/// a blocking thread pool instead of stalling the executor.
+#[cfg(feature = "async")]
+pub async fn modify_async(x: &AtomicFile, mut op: impl FnMut(&[u8]) -> Vec<u8>) -> io::Result<()> {
+    use async_std::io::ReadExt;
+
+    loop {
+        let latest = x.load_async().await?;
+        let mut buf = vec![];
+        if let Some(mut file) = latest.open_async().await? {
+            file.read_to_end(&mut buf).await?;
+        }
+        let buf = crate::atomic::strip_checksum_header(buf)?;
+        let data = op(&buf);
+        let tmp = x.make_temp_async().await?;
+        let tmp = async_std::task::spawn_blocking(move || -> io::Result<crate::atomic::TmpFile> {
+            (&tmp).write_all(&data)?;
+            (&tmp).flush()?;
+            Ok(tmp)
+        })
+        .await?;
+        match x.compare_and_swap_async(&latest, tmp).await {
+            Ok(()) => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
 pub fn modify_json<T: Serialize + DeserializeOwned>(
     x: &AtomicFile,
     mut op: impl FnMut(&mut Option<T>),
@@ -33,8 +74,8 @@ pub fn modify_json<T: Serialize + DeserializeOwned>(
     loop {
         let latest = x.load()?;
         let mut val = None;
-        if let Some(file) = latest.open()? {
-            val = Some(serde_json::from_reader(io::BufReader::new(file))?);
+        if let Some(buf) = latest.read_checked()? {
+            val = Some(serde_json::from_slice(&buf)?);
         }
         op(&mut val);
         let tmp = x.make_temp()?;
@@ -49,3 +90,38 @@ pub fn modify_json<T: Serialize + DeserializeOwned>(
         }
     }
 }
+
+/// Async equivalent of [`modify_json`], which offloads the blocking syscalls
+/// onto a blocking thread pool instead of stalling the executor.
+#[cfg(feature = "async")]
+pub async fn modify_json_async<T: Serialize + DeserializeOwned>(
+    x: &AtomicFile,
+    mut op: impl FnMut(&mut Option<T>),
+) -> io::Result<()> {
+    use async_std::io::ReadExt;
+
+    loop {
+        let latest = x.load_async().await?;
+        let mut val = None;
+        if let Some(mut file) = latest.open_async().await? {
+            let mut buf = vec![];
+            file.read_to_end(&mut buf).await?;
+            let buf = crate::atomic::strip_checksum_header(buf)?;
+            val = Some(serde_json::from_slice(&buf)?);
+        }
+        op(&mut val);
+        let tmp = x.make_temp_async().await?;
+        let data = serde_json::to_vec(&val)?;
+        let tmp = async_std::task::spawn_blocking(move || -> io::Result<crate::atomic::TmpFile> {
+            (&tmp).write_all(&data)?;
+            (&tmp).flush()?;
+            Ok(tmp)
+        })
+        .await?;
+        match x.compare_and_swap_async(&latest, tmp).await {
+            Ok(()) => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
@@ -0,0 +1,58 @@
+//! Platform-specific primitives backing the `AtomicFile` CAS protocol.
+//!
+//! Every platform must provide: creating a uniquely-named temporary file,
+//! atomically linking it into place only if the destination doesn't already
+//! exist, deciding whether two paths currently refer to the same file, and
+//! (best-effort) making a finalized version world-readable.
+
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[cfg(unix)]
+mod unix;
+#[cfg(unix)]
+use self::unix as imp;
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+use self::windows as imp;
+
+/// Create a new, uniquely-named temporary file inside `directory`.
+pub fn create_temp_file(directory: &Path) -> io::Result<(File, PathBuf)> {
+    imp::create_temp_file(directory)
+}
+
+/// Atomically create `dest` as a hard link to `src`, failing with
+/// `io::ErrorKind::AlreadyExists` if `dest` is already present.
+pub fn link_new(src: &Path, dest: &Path) -> io::Result<()> {
+    imp::link_new(src, dest)
+}
+
+/// Whether `a` and `b` currently refer to the same underlying file, e.g. by
+/// comparing `dev`+`ino` on Unix or volume+file-index on Windows. Used to
+/// tell apart "another writer's link beat ours" from "our own link actually
+/// went through, despite a racy error", since filesystems don't all report
+/// link counts consistently.
+pub fn is_same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    imp::is_same_file(a, b)
+}
+
+/// Best-effort: grant read access to everyone for the finalized version file.
+pub fn set_world_readable(path: &Path) -> io::Result<()> {
+    imp::set_world_readable(path)
+}
+
+/// Take a whole-file advisory exclusive lock on `file`. `None` blocks until
+/// acquired; `Some(timeout)` returns `io::ErrorKind::TimedOut` past that
+/// duration instead of blocking forever.
+pub fn lock_exclusive(file: &File, timeout: Option<Duration>) -> io::Result<()> {
+    imp::lock_exclusive(file, timeout)
+}
+
+/// Release a lock previously taken by [`lock_exclusive`].
+pub fn unlock(file: &File) -> io::Result<()> {
+    imp::unlock(file)
+}
@@ -0,0 +1,75 @@
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub(super) fn create_temp_file(directory: &Path) -> io::Result<(File, PathBuf)> {
+    let template = directory.join("XXXXXX");
+    let (fd, path) = nix::unistd::mkstemp(&template)?;
+    Ok((unsafe { File::from_raw_fd(fd) }, path))
+}
+
+pub(super) fn link_new(src: &Path, dest: &Path) -> io::Result<()> {
+    nix::unistd::linkat(
+        None,
+        src,
+        None,
+        dest,
+        nix::unistd::LinkatFlags::NoSymlinkFollow,
+    )
+    .map_err(io::Error::from)
+}
+
+pub(super) fn is_same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    let a = fs::metadata(a)?;
+    let b = fs::metadata(b)?;
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+pub(super) fn set_world_readable(path: &Path) -> io::Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(0o644))
+}
+
+fn whole_file_lock(l_type: libc::c_short) -> libc::flock {
+    libc::flock {
+        l_type,
+        l_whence: libc::SEEK_SET as libc::c_short,
+        l_start: 0,
+        l_len: 0,
+        l_pid: 0,
+    }
+}
+
+pub(super) fn lock_exclusive(file: &File, timeout: Option<Duration>) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    let lock = whole_file_lock(libc::F_WRLCK as libc::c_short);
+    let Some(timeout) = timeout else {
+        nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_SETLKW(&lock))?;
+        return Ok(());
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        match nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_SETLK(&lock)) {
+            Ok(_) => return Ok(()),
+            Err(nix::errno::Errno::EACCES | nix::errno::Errno::EAGAIN) => {
+                if Instant::now() >= deadline {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "timed out waiting to acquire the writer lock",
+                    ));
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+pub(super) fn unlock(file: &File) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    let lock = whole_file_lock(libc::F_UNLCK as libc::c_short);
+    nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_SETLK(&lock))?;
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::AsRawHandle;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use winapi::shared::minwindef::FALSE;
+use winapi::shared::winerror::ERROR_LOCK_VIOLATION;
+use winapi::um::fileapi::{
+    CreateHardLinkW, GetFileInformationByHandle, LockFileEx, UnlockFile,
+    BY_HANDLE_FILE_INFORMATION,
+};
+use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY, OVERLAPPED};
+use winapi::um::winnt::HANDLE;
+
+pub(super) fn create_temp_file(directory: &Path) -> io::Result<(File, PathBuf)> {
+    // There is no mkstemp(3) equivalent on Windows: synthesize a unique name
+    // and create it exclusively, retrying on collision.
+    loop {
+        let path = directory.join(format!("{:x}-{:x}", std::process::id(), unique_suffix()));
+        match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => return Ok((file, path)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn unique_suffix() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    // Mix in a stack address as a cheap extra source of entropy so that two
+    // threads racing through this function in the same nanosecond still get
+    // distinct names.
+    nanos ^ (&nanos as *const u64 as u64)
+}
+
+fn to_wide(path: &Path) -> Vec<u16> {
+    path.as_os_str().encode_wide().chain(Some(0)).collect()
+}
+
+pub(super) fn link_new(src: &Path, dest: &Path) -> io::Result<()> {
+    let src = to_wide(src);
+    let dest = to_wide(dest);
+    // SAFETY: `src` and `dest` are NUL-terminated UTF-16 strings that outlive
+    // the call.
+    let ok = unsafe { CreateHardLinkW(dest.as_ptr(), src.as_ptr(), ptr::null_mut()) };
+    if ok == FALSE.into() {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn file_info(path: &Path) -> io::Result<BY_HANDLE_FILE_INFORMATION> {
+    let file = File::open(path)?;
+    // SAFETY: `info` is fully written by `GetFileInformationByHandle` before
+    // being read.
+    unsafe {
+        let mut info: BY_HANDLE_FILE_INFORMATION = std::mem::zeroed();
+        let ok = GetFileInformationByHandle(file.as_raw_handle() as HANDLE, &mut info);
+        if ok == FALSE.into() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(info)
+    }
+}
+
+pub(super) fn is_same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    let a = file_info(a)?;
+    let b = file_info(b)?;
+    Ok(a.dwVolumeSerialNumber == b.dwVolumeSerialNumber
+        && a.nFileIndexHigh == b.nFileIndexHigh
+        && a.nFileIndexLow == b.nFileIndexLow)
+}
+
+pub(super) fn set_world_readable(_path: &Path) -> io::Result<()> {
+    // Windows has no POSIX mode bits; the file already inherits its parent
+    // directory's ACL.
+    Ok(())
+}
+
+fn try_lock_exclusive(file: &File, fail_immediately: bool) -> io::Result<bool> {
+    let handle = file.as_raw_handle() as HANDLE;
+    let mut flags = LOCKFILE_EXCLUSIVE_LOCK;
+    if fail_immediately {
+        flags |= LOCKFILE_FAIL_IMMEDIATELY;
+    }
+    // SAFETY: `overlapped` is zeroed and lives for the duration of the call;
+    // we lock the whole file by requesting the maximum range.
+    unsafe {
+        let mut overlapped: OVERLAPPED = std::mem::zeroed();
+        let ok = LockFileEx(handle, flags, 0, !0, !0, &mut overlapped);
+        if ok != 0 {
+            return Ok(true);
+        }
+    }
+    let err = io::Error::last_os_error();
+    if fail_immediately && err.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+pub(super) fn lock_exclusive(file: &File, timeout: Option<Duration>) -> io::Result<()> {
+    let Some(timeout) = timeout else {
+        try_lock_exclusive(file, false)?;
+        return Ok(());
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        if try_lock_exclusive(file, true)? {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out waiting to acquire the writer lock",
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+pub(super) fn unlock(file: &File) -> io::Result<()> {
+    let handle = file.as_raw_handle() as HANDLE;
+    // SAFETY: unlocks the whole-file range taken by `lock_exclusive`.
+    let ok = unsafe { UnlockFile(handle, 0, 0, !0, !0) };
+    if ok == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,17 @@
+use atomic_file::{modify, AtomicFile};
+use std::io::{self, Read};
+
+fn main() -> io::Result<()> {
+    let file = AtomicFile::new("file_versions")?;
+    for i in 0..3 {
+        modify(&file, |_| format!("v{i}").into_bytes())?;
+    }
+
+    let versions: Vec<_> = file.versions()?.map(|(version, _)| version).collect();
+    assert_eq!(versions, vec![3, 2, 1]);
+
+    let mut data = String::new();
+    file.load_version(2).open()?.unwrap().read_to_string(&mut data)?;
+    assert_eq!(data, "v1");
+    Ok(())
+}